@@ -0,0 +1,441 @@
+//! In-circuit mirror of [`crate::data_structures::ledger`]. Each gadget here
+//! corresponds 1:1 to a native type or function in `ledger.rs`, so a prover
+//! can be built by re-running the same native logic over `Var`s instead of
+//! plain values.
+use blake2::Blake2s;
+use ark_ed_on_bls12_381::{constraints::EdwardsVar, EdwardsProjective, Fr};
+use ark_r1cs_std::prelude::*;
+use ark_r1cs_std::fields::fp::FpVar;
+use ark_r1cs_std::uint8::UInt8;
+use ark_relations::r1cs::SynthesisError;
+use ark_crypto_primitives::crh::CRHGadget;
+use ark_crypto_primitives::crh::poseidon::constraints::{CRHGadget as PoseidonCRHGadget, CRHParametersVar};
+use ark_crypto_primitives::merkle_tree::constraints::PathVar;
+
+use crate::data_structures::ledger::{AccountInformation, MerkleConfig, STATE_HASH_DOMAIN_TAG};
+
+/// In-circuit counterpart of `MerkleTreeCRH`: the same Poseidon permutation,
+/// operating over circuit variables. Implements the pre-refactor
+/// `CRHGadget<H, ConstraintF>` trait — the generation that pairs with the
+/// 2-associated-type `merkle_tree::Config` this crate's native side uses
+/// (see [`MerkleConfig`]) — rather than the newer `CRHSchemeGadget`, which
+/// only exists alongside a `Config` trait carrying `Leaf`/`LeafDigest`/
+/// `InnerDigest` associated types that `MerkleConfig` doesn't define. Like
+/// its native counterpart, `evaluate` takes raw bytes.
+pub type MerkleTreeCRHGadget = PoseidonCRHGadget<Fr>;
+
+/// In-circuit counterpart of a [`MerkleConfig`] authentication path.
+/// Parameterized directly by the leaf and two-to-one hash gadgets (both
+/// [`MerkleTreeCRHGadget`], matching `MerkleConfig` using the same CRH for
+/// both), the way `PathVar` is shaped for a plain `LeafHash`/`TwoToOneHash`
+/// `Config` — there is no `ConfigGadget` to implement for that shape, since
+/// `ConfigGadget` belongs to the later `Config` generation this crate is
+/// not pinned to.
+pub type MerkleConfigPathVar = PathVar<MerkleConfig, Fr, MerkleTreeCRHGadget, MerkleTreeCRHGadget>;
+
+/// Serialize `x` as exactly `num_bytes` little-endian bytes, enforcing every
+/// higher-order byte is zero — i.e. that `x` actually fits in `num_bytes`.
+/// Used both to encode a field element the same fixed width the native code
+/// serializes the primitive it represents as (`u8`/`u64`, via
+/// `ark_ff::to_bytes!`), and as a range check: a witness that doesn't fit in
+/// `num_bytes` fails here instead of silently wrapping modulo the scalar
+/// field the way plain `FpVar` arithmetic would.
+fn enforce_fits_in_bytes(x: &FpVar<Fr>, num_bytes: usize) -> Result<Vec<UInt8<Fr>>, SynthesisError> {
+    let bytes = x.to_bytes()?;
+    for byte in &bytes[num_bytes..] {
+        byte.enforce_equal(&UInt8::constant(0))?;
+    }
+    Ok(bytes[..num_bytes].to_vec())
+}
+
+/// In-circuit counterpart of [`AccountInformation`]: the account's public
+/// key (kept in affine form, since that's how points are represented inside
+/// the constraint system — no `into_affine()` conversion needed) and its
+/// balance, as a scalar-field variable.
+pub struct AccountInformationVar {
+    pub public_key: EdwardsVar,
+    pub balance: FpVar<Fr>,
+}
+
+impl AccountInformationVar {
+    /// Mirrors `AccountInformation::to_bytes`: serialize the public key's
+    /// `x`/`y` coordinates and the balance as bytes the same way
+    /// `ark_ff::to_bytes!` serializes that native `Fr` triple, ready to feed
+    /// to [`MerkleTreeCRHGadget::evaluate`].
+    pub fn to_bytes_gadget(&self) -> Result<Vec<UInt8<Fr>>, SynthesisError> {
+        Ok([self.public_key.x.to_bytes()?, self.public_key.y.to_bytes()?, self.balance.to_bytes()?].concat())
+    }
+}
+
+/// Witness an [`AccountInformation`] as an [`AccountInformationVar`] in
+/// `cs`, allocating its public key and balance as circuit variables.
+pub fn alloc_account_information(
+    cs: impl Into<ark_relations::r1cs::Namespace<Fr>>,
+    account_info: Option<&AccountInformation>,
+    mode: AllocationMode,
+) -> Result<AccountInformationVar, SynthesisError> {
+    let ns = cs.into();
+    let cs = ns.cs();
+    let public_key = EdwardsVar::new_variable(
+        ark_relations::ns!(cs, "public_key"),
+        || account_info.map(|info| info.public_key_affine()).ok_or(SynthesisError::AssignmentMissing),
+        mode,
+    )?;
+    let balance = FpVar::new_variable(
+        ark_relations::ns!(cs, "balance"),
+        || account_info.map(|info| Fr::from(info.balance_value())).ok_or(SynthesisError::AssignmentMissing),
+        mode,
+    )?;
+    Ok(AccountInformationVar { public_key, balance })
+}
+
+/// In-circuit counterpart of `Transaction`'s semantic fields — everything
+/// `Transaction::message` and `Transaction::nullifier` depend on.
+pub struct TransactionVar {
+    pub sender: FpVar<Fr>,
+    pub recipient: FpVar<Fr>,
+    pub amount: FpVar<Fr>,
+    pub nonce: FpVar<Fr>,
+}
+
+impl TransactionVar {
+    /// Mirrors `Transaction::nullifier`: absorbs only the deterministic
+    /// semantic fields — never anything signature-dependent — as the same
+    /// fixed-width bytes the native code serializes them as (`u8`/`u8`/
+    /// `u64`/`u64`/`u64`), through [`MerkleTreeCRHGadget`], so a re-signed
+    /// replay of the same transfer yields the same nullifier. The nullifier
+    /// tree check in [`enforce_transaction_step`] proves only that the slot
+    /// being filled was blank; it does not check that this nullifier value
+    /// is absent from the tree's other slots.
+    pub fn nullifier_var(
+        &self,
+        leaf_crh_params: &CRHParametersVar<Fr>,
+        external_nullifier: &FpVar<Fr>,
+    ) -> Result<FpVar<Fr>, SynthesisError> {
+        let bytes = [
+            enforce_fits_in_bytes(&self.sender, 1)?,
+            enforce_fits_in_bytes(&self.recipient, 1)?,
+            enforce_fits_in_bytes(&self.amount, 8)?,
+            enforce_fits_in_bytes(&self.nonce, 8)?,
+            enforce_fits_in_bytes(external_nullifier, 8)?,
+        ].concat();
+        MerkleTreeCRHGadget::evaluate(leaf_crh_params, &bytes)
+    }
+}
+
+/// Leaf bytes for a not-yet-spent nullifier-tree slot, mirroring the empty
+/// byte string `MerkleTree::blank` hashes into every leaf before any
+/// `update` call.
+fn blank_nullifier_leaf() -> Vec<UInt8<Fr>> {
+    Vec::new()
+}
+
+/// Extension point for enforcing, inside the constraint system, that a
+/// transfer's signature is valid — the in-circuit counterpart of the native
+/// `SignatureScheme::verify` check `State::apply_transaction` now performs.
+/// Kept as a trait rather than inlining one concrete signature scheme's
+/// transcript, so a block circuit built on [`enforce_transaction_step`] can chain whichever
+/// gadget backs `Parameters::sig_params` without this module depending on
+/// its internals. [`ConcreteTransferSignatureGadget`] is the implementation
+/// backing `Parameters::sig_params` as written (`schnorr::Schnorr<
+/// EdwardsProjective, Blake2s>`).
+pub trait TransferSignatureGadget {
+    type ParametersVar;
+    type PublicKeyVar;
+    type SignatureVar;
+
+    /// Enforce that `signature` is valid for `public_key` over `message`.
+    /// Implementations must reject exactly when the native
+    /// `SignatureScheme::verify` they mirror would return `Ok(false)` (or
+    /// `Err`).
+    fn enforce_verified(
+        parameters: &Self::ParametersVar,
+        public_key: &Self::PublicKeyVar,
+        message: &[UInt8<Fr>],
+        signature: &Self::SignatureVar,
+    ) -> Result<(), SynthesisError>;
+}
+
+/// [`TransferSignatureGadget`] backing `Parameters::sig_params`: the
+/// constraint-system counterpart of `schnorr::Schnorr<EdwardsProjective,
+/// Blake2s>`, delegating to `ark_crypto_primitives`'s own Schnorr gadget for
+/// its own `Schnorr` scheme rather than re-deriving that transcript here.
+pub struct ConcreteTransferSignatureGadget;
+
+impl TransferSignatureGadget for ConcreteTransferSignatureGadget {
+    type ParametersVar = ark_crypto_primitives::signature::schnorr::constraints::ParametersVar<EdwardsProjective, EdwardsVar>;
+    type PublicKeyVar = EdwardsVar;
+    type SignatureVar = ark_crypto_primitives::signature::schnorr::constraints::SignatureVar<EdwardsProjective, EdwardsVar>;
+
+    fn enforce_verified(
+        parameters: &Self::ParametersVar,
+        public_key: &Self::PublicKeyVar,
+        message: &[UInt8<Fr>],
+        signature: &Self::SignatureVar,
+    ) -> Result<(), SynthesisError> {
+        use ark_crypto_primitives::signature::SigVerifyGadget;
+        let is_valid = ark_crypto_primitives::signature::schnorr::constraints::SchnorrSignatureVerifyGadget::<
+            EdwardsProjective,
+            EdwardsVar,
+            Blake2s,
+        >::verify(parameters, public_key, message, signature)?;
+        is_valid.enforce_equal(&Boolean::TRUE)
+    }
+}
+
+/// Everything one step of the block circuit needs to re-derive
+/// `State::apply_transaction`'s effect on the account tree — the in-circuit
+/// counterpart of a native [`crate::data_structures::ledger::TransactionWitness`].
+pub struct TransactionStepVar<Sig: TransferSignatureGadget<PublicKeyVar = EdwardsVar>> {
+    pub tx: TransactionVar,
+    pub signature: Sig::SignatureVar,
+    pub sender_info: AccountInformationVar,
+    pub sender_path: MerkleConfigPathVar,
+    pub receiver_info: AccountInformationVar,
+    pub receiver_path: MerkleConfigPathVar,
+    /// Authentication path to the nullifier-tree slot this transaction's
+    /// nullifier will occupy — `next_nullifier_index` at the time this step
+    /// was witnessed (see `TransactionWitness::nullifier_path`) — proving
+    /// that slot was still blank under `pre_nullifier_root`.
+    pub nullifier_path: MerkleConfigPathVar,
+    pub external_nullifier: FpVar<Fr>,
+}
+
+/// Enforce one step of the block circuit — the in-circuit counterpart of a
+/// single [`crate::data_structures::ledger::State::apply_transaction`] call
+/// spliced into [`crate::data_structures::ledger::State::apply_block`]'s loop:
+///
+/// 1. the sender's signature over `step.tx` verifies under `sig_params`;
+/// 2. `step.sender_info`/`step.receiver_info` are exactly the leaves at
+///    `step.tx.sender`/`step.tx.recipient` under `pre_account_root`;
+/// 3. `step.tx`'s nullifier slot is proved blank under `pre_nullifier_root`,
+///    then spliced in with the real nullifier value (the nullifier value is
+///    not checked against the tree's other slots);
+/// 4. the post-transfer balances are spliced back into the account tree.
+///
+/// Returns the chained `(account_root, nullifier_root)` pair, to be passed
+/// as the `pre_*_root`s of the next step.
+///
+/// Unlike `apply_block`'s native pre-validation pass, there is no separate
+/// atomicity concern here: an R1CS instance is either fully satisfied or
+/// not, so a single unsatisfied step fails the whole block's proof.
+pub fn enforce_transaction_step<Sig: TransferSignatureGadget<PublicKeyVar = EdwardsVar>>(
+    leaf_crh_params: &CRHParametersVar<Fr>,
+    two_to_one_crh_params: &CRHParametersVar<Fr>,
+    sig_params: &Sig::ParametersVar,
+    step: &TransactionStepVar<Sig>,
+    pre_account_root: &FpVar<Fr>,
+    pre_nullifier_root: &FpVar<Fr>,
+) -> Result<(FpVar<Fr>, FpVar<Fr>), SynthesisError> {
+    // Fixed-width per field (u8/u8/u64/u64), matching `Transaction::message`'s
+    // `ark_ff::to_bytes!` encoding exactly — a generic `FpVar::to_bytes()`
+    // would instead serialize each field at its full ~32-byte field width,
+    // which is a different byte string than anything `Schnorr::sign` ever
+    // actually signs.
+    let message = [
+        enforce_fits_in_bytes(&step.tx.sender, 1)?,
+        enforce_fits_in_bytes(&step.tx.recipient, 1)?,
+        enforce_fits_in_bytes(&step.tx.amount, 8)?,
+        enforce_fits_in_bytes(&step.tx.nonce, 8)?,
+    ].concat();
+    Sig::enforce_verified(sig_params, &step.sender_info.public_key, &message, &step.signature)?;
+
+    step.sender_path
+        .verify_membership(leaf_crh_params, two_to_one_crh_params, pre_account_root, &step.sender_info.to_bytes_gadget()?)?
+        .enforce_equal(&Boolean::TRUE)?;
+    step.receiver_path
+        .verify_membership(leaf_crh_params, two_to_one_crh_params, pre_account_root, &step.receiver_info.to_bytes_gadget()?)?
+        .enforce_equal(&Boolean::TRUE)?;
+
+    // Nullifier non-membership: the slot this transaction's nullifier will
+    // occupy must still be blank under `pre_nullifier_root`, mirroring the
+    // way `apply_transaction` only ever inserts at a slot the append-only
+    // `next_nullifier_index` has not reached yet.
+    step.nullifier_path
+        .verify_membership(leaf_crh_params, two_to_one_crh_params, pre_nullifier_root, &blank_nullifier_leaf())?
+        .enforce_equal(&Boolean::TRUE)?;
+
+    // Bound sender balance and amount to 64 bits (mirroring `Amount`'s
+    // native `u64` representation) and require `amount <= balance`, so the
+    // subtraction below can't silently wrap modulo the scalar field the way
+    // plain `FpVar` arithmetic would if a prover supplied an oversized
+    // `amount`.
+    enforce_fits_in_bytes(&step.sender_info.balance, 8)?;
+    enforce_fits_in_bytes(&step.tx.amount, 8)?;
+    step.tx.amount.enforce_cmp(&step.sender_info.balance, core::cmp::Ordering::Less, true)?;
+
+    let updated_sender = AccountInformationVar {
+        public_key: step.sender_info.public_key.clone(),
+        balance: &step.sender_info.balance - &step.tx.amount,
+    };
+    let updated_receiver_balance = &step.receiver_info.balance + &step.tx.amount;
+    // And the receiver's new balance must itself still fit in 64 bits, so
+    // the addition above can't silently overflow into a huge field element.
+    enforce_fits_in_bytes(&updated_receiver_balance, 8)?;
+    let updated_receiver = AccountInformationVar {
+        public_key: step.receiver_info.public_key.clone(),
+        balance: updated_receiver_balance,
+    };
+
+    let root_after_sender =
+        step.sender_path.update_leaf(leaf_crh_params, two_to_one_crh_params, &updated_sender.to_bytes_gadget()?)?;
+    step.receiver_path
+        .verify_membership(leaf_crh_params, two_to_one_crh_params, &root_after_sender, &step.receiver_info.to_bytes_gadget()?)?
+        .enforce_equal(&Boolean::TRUE)?;
+    let new_account_root =
+        step.receiver_path.update_leaf(leaf_crh_params, two_to_one_crh_params, &updated_receiver.to_bytes_gadget()?)?;
+
+    let nullifier = step.tx.nullifier_var(leaf_crh_params, &step.external_nullifier)?;
+    let new_nullifier_root =
+        step.nullifier_path.update_leaf(leaf_crh_params, two_to_one_crh_params, &nullifier.to_bytes()?)?;
+
+    Ok((new_account_root, new_nullifier_root))
+}
+
+/// Mirrors `State::recompute_state_hash`: folds the domain tag, account
+/// tree root, and live account count into the single field element clients
+/// and the rollup circuit use to reference the whole ledger. A block
+/// circuit calls this on the chained post-root and the account count after
+/// the batch, and exposes the result as a public input, so the verifier is
+/// convinced the prover's claimed post-state matches the root the circuit
+/// itself just finished chaining — rather than trusting an unconstrained
+/// claim.
+pub fn state_hash_var(
+    leaf_crh_params: &CRHParametersVar<Fr>,
+    root: &FpVar<Fr>,
+    account_count: &FpVar<Fr>,
+) -> Result<FpVar<Fr>, SynthesisError> {
+    // `STATE_HASH_DOMAIN_TAG` and the account count are serialized as `u64`s
+    // natively, not full field elements — mirror that with an 8-byte
+    // encoding of each; `root` is already a native `Fr`, so it keeps its
+    // full width.
+    let domain_tag = enforce_fits_in_bytes(&FpVar::constant(Fr::from(STATE_HASH_DOMAIN_TAG)), 8)?;
+    let count_bytes = enforce_fits_in_bytes(account_count, 8)?;
+    let bytes = [domain_tag, root.to_bytes()?, count_bytes].concat();
+    MerkleTreeCRHGadget::evaluate(leaf_crh_params, &bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ark_crypto_primitives::signature::{schnorr, SignatureScheme};
+    use ark_relations::r1cs::ConstraintSystem;
+    use ark_std::test_rng;
+
+    use crate::data_structures::ledger::{AccountId, Amount, ExternalNullifier, Parameters, State, Transaction};
+
+    /// Mirrors the private `Transaction::message` encoding exactly, so the
+    /// signature produced here is one `State::apply_transaction` would
+    /// itself accept.
+    fn signed_transfer(
+        parameters: &Parameters,
+        sender: AccountId,
+        recipient: AccountId,
+        amount: u64,
+        nonce: u64,
+        sender_sk: &schnorr::SecretKey<EdwardsProjective>,
+    ) -> Transaction {
+        let rng = &mut test_rng();
+        let message = ark_ff::to_bytes![sender.0, recipient.0, amount, nonce].unwrap();
+        let signature = schnorr::Schnorr::<EdwardsProjective, Blake2s>::sign(
+            &parameters.sig_params,
+            sender_sk,
+            &message,
+            rng,
+        ).unwrap();
+        Transaction { sender, recipient, amount: Amount(amount), nonce, signature }
+    }
+
+    /// Runs a single transfer through `State::apply_block` natively, then
+    /// re-derives the same step inside the constraint system and checks it
+    /// is satisfied — an end-to-end exercise of `enforce_transaction_step`
+    /// (signature, account membership/update, and nullifier
+    /// non-membership/insertion together), not just its pieces in
+    /// isolation.
+    #[test]
+    fn enforce_transaction_step_is_satisfied_for_a_valid_transfer() {
+        let rng = &mut test_rng();
+        let parameters = Parameters::setup(rng);
+        let mut state = State::new(8, &parameters);
+
+        let (alice_pk, alice_sk) =
+            schnorr::Schnorr::<EdwardsProjective, Blake2s>::keygen(&parameters.sig_params, rng).unwrap();
+        let (bob_pk, _) = schnorr::Schnorr::<EdwardsProjective, Blake2s>::keygen(&parameters.sig_params, rng).unwrap();
+        let alice = AccountId(0);
+        let bob = AccountId(1);
+        state.new_account(alice, alice_pk);
+        state.new_account(bob, bob_pk);
+        state.update_balance(alice, Amount(100)).unwrap();
+
+        let external_nullifier = ExternalNullifier(0);
+        let pre_nullifier_root = state.nullifier_tree_root();
+        let tx = signed_transfer(&parameters, alice, bob, 10, 0, &alice_sk);
+        let block_witness = state
+            .apply_block(&parameters, &[tx.clone()], external_nullifier)
+            .expect("well-formed transfer must apply");
+        let post_nullifier_root = state.nullifier_tree_root();
+        let tx_witness = &block_witness.transactions[0];
+
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        let leaf_crh_params = CRHParametersVar::new_constant(cs.clone(), parameters.leaf_crh_params.clone()).unwrap();
+        let two_to_one_crh_params =
+            CRHParametersVar::new_constant(cs.clone(), parameters.two_to_one_crh_params.clone()).unwrap();
+        let sig_params = <ConcreteTransferSignatureGadget as TransferSignatureGadget>::ParametersVar::new_constant(
+            cs.clone(),
+            parameters.sig_params.clone(),
+        )
+        .unwrap();
+
+        let sender_info = alloc_account_information(cs.clone(), Some(&tx_witness.sender_leaf), AllocationMode::Witness).unwrap();
+        let receiver_info = alloc_account_information(cs.clone(), Some(&tx_witness.receiver_leaf), AllocationMode::Witness).unwrap();
+        let sender_path = MerkleConfigPathVar::new_witness(cs.clone(), || Ok(tx_witness.sender_path.clone())).unwrap();
+        let receiver_path = MerkleConfigPathVar::new_witness(cs.clone(), || Ok(tx_witness.receiver_path.clone())).unwrap();
+        let nullifier_path = MerkleConfigPathVar::new_witness(cs.clone(), || Ok(tx_witness.nullifier_path.clone())).unwrap();
+        let signature =
+            <ConcreteTransferSignatureGadget as TransferSignatureGadget>::SignatureVar::new_witness(cs.clone(), || {
+                Ok(tx.signature.clone())
+            })
+            .unwrap();
+
+        let tx_var = TransactionVar {
+            sender: FpVar::new_witness(cs.clone(), || Ok(Fr::from(tx.sender.0 as u64))).unwrap(),
+            recipient: FpVar::new_witness(cs.clone(), || Ok(Fr::from(tx.recipient.0 as u64))).unwrap(),
+            amount: FpVar::new_witness(cs.clone(), || Ok(Fr::from(tx.amount.0))).unwrap(),
+            nonce: FpVar::new_witness(cs.clone(), || Ok(Fr::from(tx.nonce))).unwrap(),
+        };
+        let external_nullifier_var = FpVar::new_witness(cs.clone(), || Ok(Fr::from(external_nullifier.0))).unwrap();
+
+        let step = TransactionStepVar::<ConcreteTransferSignatureGadget> {
+            tx: tx_var,
+            signature,
+            sender_info,
+            sender_path,
+            receiver_info,
+            receiver_path,
+            nullifier_path,
+            external_nullifier: external_nullifier_var,
+        };
+
+        let pre_account_root = FpVar::new_witness(cs.clone(), || Ok(block_witness.pre_state_root)).unwrap();
+        let pre_nullifier_root_var = FpVar::new_witness(cs.clone(), || Ok(pre_nullifier_root)).unwrap();
+
+        let (new_account_root, new_nullifier_root) = enforce_transaction_step(
+            &leaf_crh_params,
+            &two_to_one_crh_params,
+            &sig_params,
+            &step,
+            &pre_account_root,
+            &pre_nullifier_root_var,
+        )
+        .unwrap();
+
+        new_account_root
+            .enforce_equal(&FpVar::new_witness(cs.clone(), || Ok(block_witness.post_state_root)).unwrap())
+            .unwrap();
+        new_nullifier_root
+            .enforce_equal(&FpVar::new_witness(cs.clone(), || Ok(post_nullifier_root)).unwrap())
+            .unwrap();
+
+        assert!(cs.is_satisfied().unwrap());
+    }
+}
@@ -1,8 +1,11 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use blake2::Blake2s;
-use ark_ed_on_bls12_381::EdwardsProjective;
-use ark_crypto_primitives::signature::schnorr;
-use ark_crypto_primitives::crh::{CRH, pedersen, injective_map::{PedersenCRHCompressor, TECompressor}};
+use ark_ff::PrimeField;
+use ark_ec::ProjectiveCurve;
+use ark_ed_on_bls12_381::{EdwardsProjective, Fr};
+use ark_crypto_primitives::signature::{schnorr, SignatureScheme};
+use ark_crypto_primitives::crh::{CRH, poseidon};
+use ark_crypto_primitives::crh::poseidon::find_poseidon_ark_and_mds;
 use ark_crypto_primitives::merkle_tree::{self, MerkleTree};
 
 
@@ -11,11 +14,11 @@ pub type AccountPublicKey = schnorr::PublicKey<EdwardsProjective>;
 
 /// Account ID.
 #[derive(Hash, Eq, PartialEq, Copy, Clone, Ord, PartialOrd)]
-pub struct AccountId(u8);
+pub struct AccountId(pub(crate) u8);
 
 /// Transaction amount.
 #[derive(Hash, Eq, PartialEq, Copy, Clone)]
-pub struct Amount(u64);
+pub struct Amount(pub(crate) u64);
 
 
 #[derive(Hash, Eq, PartialEq, Copy, Clone)]
@@ -25,10 +28,31 @@ pub struct AccountInformation {
 }
 
 impl AccountInformation {
+    /// Pack this account's public key coordinates and balance into
+    /// scalar-field elements. The leaf hash is fed bytes, not field
+    /// elements: [`Self::to_bytes`] serializes these three elements for it.
+    ///
+    /// `public_key` is a `Projective` point, which has no `x`/`y`
+    /// coordinates of its own; it's converted to `Affine` first.
+    fn to_field_elements(&self) -> Vec<Fr> {
+        let (pk_x, pk_y) = self.public_key.into_affine().xy().expect("public key is not the identity");
+        vec![pk_x, pk_y, Fr::from(self.balance.0)]
+    }
+
     fn to_bytes(&self) -> Vec<u8> {
-        ark_ff::to_bytes![self.public_key, self.balance.0].unwrap()
+        ark_ff::to_bytes![self.to_field_elements()].unwrap()
     }
 
+    /// Affine form of the public key, for the [`crate::constraints`]
+    /// gadgets, which represent curve points in affine form natively.
+    pub(crate) fn public_key_affine(&self) -> ark_ed_on_bls12_381::EdwardsAffine {
+        self.public_key.into_affine()
+    }
+
+    /// Raw balance, for [`crate::constraints`].
+    pub(crate) fn balance_value(&self) -> u64 {
+        self.balance.0
+    }
 }
 
 pub struct Parameters {
@@ -37,31 +61,153 @@ pub struct Parameters {
     pub two_to_one_crh_params: <MerkleTreeCRH as CRH>::Parameters,
 }
 
-pub type MerkleTreeCRH = PedersenCRHCompressor<EdwardsProjective, TECompressor, TwoToOneWindow>;
+impl Parameters {
+    /// Generate fresh Poseidon round parameters for the leaf hash (sponge
+    /// width `t = 2`: rate 1, capacity 1) and the two-to-one hash (`t = 3`:
+    /// rate 2, capacity 1), alongside Schnorr signature parameters.
+    pub fn setup<R: ark_std::rand::Rng>(rng: &mut R) -> Self {
+        Self {
+            sig_params: schnorr::Schnorr::<EdwardsProjective, Blake2s>::setup(rng).unwrap(),
+            leaf_crh_params: poseidon_parameters_for_width(2),
+            two_to_one_crh_params: poseidon_parameters_for_width(3),
+        }
+    }
+}
 
-#[derive(Clone, PartialEq, Eq, Hash)]
-pub struct TwoToOneWindow;
+/// S-box exponent. `gcd(5, p - 1) = 1` for the BLS12-381 scalar field, so
+/// `x -> x^5` is a permutation.
+const POSEIDON_ALPHA: u64 = 5;
+/// Full rounds, split evenly before and after the partial rounds.
+const POSEIDON_FULL_ROUNDS: usize = 8;
+/// Partial rounds; chosen for the 128-bit security margin at these widths.
+const POSEIDON_PARTIAL_ROUNDS: usize = 56;
 
-// `WINDOW_SIZE * NUM_WINDOWS` = 2 * 256 bits = enough for hashing two outputs.
-impl pedersen::Window for TwoToOneWindow {
-    const WINDOW_SIZE: usize = 128;
-    const NUM_WINDOWS: usize = 4;
+/// Build Poseidon parameters for a sponge of width `t` (`rate = t - 1`,
+/// `capacity = 1`), deriving the round constants and MDS matrix the same way
+/// the reference implementation does.
+fn poseidon_parameters_for_width(t: usize) -> poseidon::PoseidonParameters<Fr> {
+    let rate = t - 1;
+    let (ark, mds) = find_poseidon_ark_and_mds::<Fr>(
+        Fr::MODULUS_BIT_SIZE as u64,
+        rate,
+        POSEIDON_FULL_ROUNDS as u64,
+        POSEIDON_PARTIAL_ROUNDS as u64,
+        0,
+    );
+    poseidon::PoseidonParameters::new(
+        POSEIDON_FULL_ROUNDS,
+        POSEIDON_PARTIAL_ROUNDS,
+        POSEIDON_ALPHA,
+        mds,
+        ark,
+        rate,
+        1,
+    )
 }
 
+/// The CRH used throughout the account Merkle tree. Both the leaf hash and
+/// the two-to-one (compression) hash are instances of this same Poseidon
+/// permutation, distinguished only by the sponge width baked into their
+/// `Parameters` (see [`Parameters::setup`]).
+pub type MerkleTreeCRH = poseidon::CRH<Fr>;
+
 pub struct MerkleConfig;
 impl merkle_tree::Config for MerkleConfig {
     type LeafHash = MerkleTreeCRH;
     type TwoToOneHash = MerkleTreeCRH;
 }
 
+/// A signed transfer of `amount` from `sender` to `recipient`. `nonce` is
+/// chosen by the sender and, together with the other transfer fields and the
+/// block's [`ExternalNullifier`] (but not the signature), binds the
+/// transaction to a unique [`Nullifier`] so it can only ever be applied once.
+#[derive(Clone)]
+pub struct Transaction {
+    pub sender: AccountId,
+    pub recipient: AccountId,
+    pub amount: Amount,
+    pub nonce: u64,
+    pub signature: schnorr::Signature<EdwardsProjective>,
+}
+
+impl Transaction {
+    /// Bytes a valid signature over this transaction must cover: the
+    /// semantic transfer fields alone. `external_nullifier` is deliberately
+    /// excluded — it's assigned when the transfer is scheduled into a block,
+    /// which happens after the sender has already signed.
+    fn message(&self) -> Vec<u8> {
+        ark_ff::to_bytes![self.sender.0, self.recipient.0, self.amount.0, self.nonce].unwrap()
+    }
+
+    /// Derive this transaction's nullifier: `H(sender || recipient || amount
+    /// || nonce || external_nullifier)`. Built only from these deterministic
+    /// semantic fields (never from `signature`, which is randomized on every
+    /// `Schnorr::sign` call) so that re-signing and resubmitting the exact
+    /// same transfer always collides with the first submission instead of
+    /// minting a fresh nullifier. Mixing in `external_nullifier` (e.g. the
+    /// rollup epoch) means the same sender/recipient/amount/nonce tuple
+    /// produces an unlinkable nullifier in each epoch, rather than colliding
+    /// across them.
+    fn nullifier(&self, parameters: &Parameters, external_nullifier: ExternalNullifier) -> Nullifier {
+        let bytes = ark_ff::to_bytes![
+            self.sender.0,
+            self.recipient.0,
+            self.amount.0,
+            self.nonce,
+            external_nullifier.0
+        ].unwrap();
+        MerkleTreeCRH::evaluate(&parameters.leaf_crh_params, &bytes).unwrap()
+    }
+}
+
+/// Domain-separation tag mixed into every nullifier, e.g. the current rollup
+/// epoch. Prevents nullifiers derived from the same sender/nonce in one
+/// epoch from being linkable to, or colliding with, another epoch's.
+#[derive(Copy, Clone)]
+pub struct ExternalNullifier(pub u64);
+
+/// Output of the nullifier hash; spent nullifiers are tracked by this value
+/// alone; it reveals nothing about the transaction that produced it.
+pub type Nullifier = <MerkleTreeCRH as CRH>::Output;
+
 pub struct State {
-    pub account_merkle_tree: MerkleTree<MerkleConfig>,
-    pub id_to_account_info: HashMap<AccountId, AccountInformation>,
+    /// Private: every mutation has to go through a `State` method so
+    /// `state_hash` (see [`Self::state_hash`]) can never drift out of sync
+    /// with the real root. Read it via [`Self::account_tree_root`].
+    account_merkle_tree: MerkleTree<MerkleConfig>,
+    /// Merkle tree of spent-transaction nullifiers, grown by one leaf per
+    /// applied transaction. Its root lets a verifier check that the slot a
+    /// nullifier is inserted at was still blank; it does not show that the
+    /// same nullifier value is absent from the other slots, which natively
+    /// only `seen_nullifiers` checks.
+    /// Private for the same reason as `account_merkle_tree`; read it via
+    /// [`Self::nullifier_tree_root`].
+    nullifier_merkle_tree: MerkleTree<MerkleConfig>,
+    /// Private so balances can't be mutated without going through
+    /// [`Self::update_balance`], which keeps `state_hash` current. Read it
+    /// via [`Self::account_info`].
+    id_to_account_info: HashMap<AccountId, AccountInformation>,
     pub pub_key_to_id: HashMap<schnorr::PublicKey<EdwardsProjective>, AccountId>,
+    seen_nullifiers: HashSet<Nullifier>,
+    next_nullifier_index: usize,
+    /// Own copy of the leaf CRH parameters, kept around so `state_hash` can
+    /// be recomputed on every mutation without threading `Parameters`
+    /// through `new_account`/`update_balance`.
+    leaf_crh_params: <MerkleTreeCRH as CRH>::Parameters,
+    /// Cached digest committing to the whole ledger; see [`Self::state_hash`].
+    state_hash: <MerkleTreeCRH as CRH>::Output,
+    /// Height of `account_merkle_tree`, cached so `partial_proof` doesn't
+    /// need to re-derive it from the account capacity.
+    height: usize,
 }
 
+/// Domain tag folded into [`State::state_hash`] so the digest cannot be
+/// confused with some other hash computed over the same root and count.
+pub(crate) const STATE_HASH_DOMAIN_TAG: u64 = 0x5354_4154_4521_0001; // "STATE!" + version
+
 impl State {
-    /// Create an empty ledger that supports `num_accounts` accounts.
+    /// Create an empty ledger that supports `num_accounts` accounts and as
+    /// many spent nullifiers.
     pub fn new(num_accounts: usize, parameters: &Parameters) -> Self {
         let height = ark_std::log2(num_accounts);
         let account_merkle_tree = MerkleTree::blank(
@@ -69,13 +215,67 @@ impl State {
             &parameters.two_to_one_crh_params,
             height as usize,
         ).unwrap();
+        let nullifier_merkle_tree = MerkleTree::blank(
+            &parameters.leaf_crh_params,
+            &parameters.two_to_one_crh_params,
+            height as usize,
+        ).unwrap();
         let pub_key_to_id = HashMap::with_capacity(num_accounts);
         let id_to_account_info = HashMap::with_capacity(num_accounts);
-        Self {
+        let state_hash = Nullifier::default();
+        let mut state = Self {
             account_merkle_tree,
+            nullifier_merkle_tree,
             id_to_account_info,
             pub_key_to_id,
-        }
+            seen_nullifiers: HashSet::new(),
+            next_nullifier_index: 0,
+            leaf_crh_params: parameters.leaf_crh_params.clone(),
+            state_hash,
+            height: height as usize,
+        };
+        state.recompute_state_hash();
+        state
+    }
+
+    /// Recompute the cached [`Self::state_hash`] from the current account
+    /// tree root and account count: `state_hash = H(tag || root ||
+    /// account_count)`. Called at the end of every mutating method, so
+    /// `state_hash()` is always current — a guarantee that only holds
+    /// because `account_merkle_tree` and `id_to_account_info` are private:
+    /// there is no way to touch either without going through a `State`
+    /// method that calls this afterwards.
+    fn recompute_state_hash(&mut self) {
+        let bytes = ark_ff::to_bytes![
+            STATE_HASH_DOMAIN_TAG,
+            self.account_merkle_tree.root(),
+            self.id_to_account_info.len() as u64
+        ].unwrap();
+        self.state_hash = MerkleTreeCRH::evaluate(&self.leaf_crh_params, &bytes).unwrap();
+    }
+
+    /// A single field element committing to the entire ledger: its account
+    /// tree root, live account count, and a domain tag, folded into one
+    /// digest via [`Self::recompute_state_hash`]. Lets clients and the
+    /// rollup circuit reference the whole ledger by a short commitment
+    /// instead of the full tree.
+    pub fn state_hash(&self) -> Nullifier {
+        self.state_hash
+    }
+
+    /// Root of `account_merkle_tree`, the tree `state_hash` commits to.
+    pub fn account_tree_root(&self) -> <MerkleTreeCRH as CRH>::Output {
+        self.account_merkle_tree.root()
+    }
+
+    /// Root of `nullifier_merkle_tree`, the spent-nullifier tree.
+    pub fn nullifier_tree_root(&self) -> <MerkleTreeCRH as CRH>::Output {
+        self.nullifier_merkle_tree.root()
+    }
+
+    /// Look up a registered account's public information by id.
+    pub fn account_info(&self, id: AccountId) -> Option<&AccountInformation> {
+        self.id_to_account_info.get(&id)
     }
 
     /// Create a new account with account identifier `id` and public key `pub_key`.
@@ -88,6 +288,7 @@ impl State {
         self.pub_key_to_id.insert(public_key, id);
         self.account_merkle_tree.update(id.0 as usize, &account_info.to_bytes()).expect("should exist");
         self.id_to_account_info.insert(id, account_info);
+        self.recompute_state_hash();
     }
 
 
@@ -96,9 +297,565 @@ impl State {
     /// otherwise.
     pub fn update_balance(&mut self, id: AccountId, new_amount: Amount) -> Option<()> {
         let tree = &mut self.account_merkle_tree;
-        self.id_to_account_info.get_mut(&id).map(|account_info| {
+        let result = self.id_to_account_info.get_mut(&id).map(|account_info| {
             account_info.balance = new_amount;
             tree.update(id.0 as usize, &account_info.to_bytes()).expect("should exist");
-        })
+        });
+        if result.is_some() {
+            self.recompute_state_hash();
+        }
+        result
+    }
+
+    /// Apply a signed transfer, enforcing replay protection: the
+    /// transaction's nullifier must not already be present in
+    /// `nullifier_merkle_tree`. On success the nullifier is inserted before
+    /// balances are mutated, so a transaction can never be applied twice.
+    ///
+    /// Returns `None` (and leaves `self` unchanged) if `tx.signature` does
+    /// not verify against the sender's public key and [`Transaction::message`],
+    /// the nullifier has already been spent, `nullifier_merkle_tree` has no
+    /// remaining capacity, either account does not exist, or the sender's
+    /// balance is insufficient to cover `tx.amount`.
+    pub fn apply_transaction(
+        &mut self,
+        parameters: &Parameters,
+        tx: &Transaction,
+        external_nullifier: ExternalNullifier,
+    ) -> Option<()> {
+        let sender_public_key = self.id_to_account_info.get(&tx.sender)?.public_key;
+        let signature_valid = schnorr::Schnorr::<EdwardsProjective, Blake2s>::verify(
+            &parameters.sig_params,
+            &sender_public_key,
+            &tx.message(),
+            &tx.signature,
+        ).ok()?;
+        if !signature_valid {
+            return None;
+        }
+
+        let nullifier = tx.nullifier(parameters, external_nullifier);
+        if self.seen_nullifiers.contains(&nullifier) {
+            return None;
+        }
+        if self.next_nullifier_index >= (1usize << self.height) {
+            return None;
+        }
+
+        let sender_balance = self.id_to_account_info.get(&tx.sender)?.balance.0;
+        let recipient_balance = self.id_to_account_info.get(&tx.recipient)?.balance.0;
+        let new_sender_balance = sender_balance.checked_sub(tx.amount.0)?;
+        let new_recipient_balance = recipient_balance.checked_add(tx.amount.0)?;
+
+        self.nullifier_merkle_tree
+            .update(self.next_nullifier_index, &ark_ff::to_bytes![nullifier].unwrap())
+            .expect("should exist");
+        self.seen_nullifiers.insert(nullifier);
+        self.next_nullifier_index += 1;
+
+        self.update_balance(tx.sender, Amount(new_sender_balance))
+            .expect("sender should exist");
+        self.update_balance(tx.recipient, Amount(new_recipient_balance))
+            .expect("recipient should exist");
+
+        Some(())
+    }
+
+    /// Apply an ordered batch of signed transfers as a single rollup block,
+    /// returning a [`BlockWitness`] that lets one circuit verify the whole
+    /// batch's state transition instead of proving each transfer separately.
+    ///
+    /// The whole batch is checked against a scratch copy of the state via
+    /// [`Self::validate_block`] *before* anything is mutated, so this call
+    /// is atomic: either every transaction in `txs` applies and `self`
+    /// reflects the full batch, or none of them do and `self` is left
+    /// exactly as it was. Each transaction's authentication paths are then
+    /// captured *before* it is applied — against the root as it stood after
+    /// every earlier transaction in `txs` — which is what the block circuit
+    /// chains.
+    ///
+    /// Returns `None`, leaving `self` unchanged, if any transaction in the
+    /// batch would be rejected by [`Self::apply_transaction`].
+    pub fn apply_block(
+        &mut self,
+        parameters: &Parameters,
+        txs: &[Transaction],
+        external_nullifier: ExternalNullifier,
+    ) -> Option<BlockWitness> {
+        self.validate_block(parameters, txs, external_nullifier)?;
+
+        let pre_state_root = self.account_merkle_tree.root();
+        let mut transactions = Vec::with_capacity(txs.len());
+
+        for tx in txs {
+            let sender_leaf = *self.id_to_account_info.get(&tx.sender).expect("already validated by validate_block");
+            let receiver_leaf = *self.id_to_account_info.get(&tx.recipient).expect("already validated by validate_block");
+            let sender_path = self.account_merkle_tree.generate_proof(tx.sender.0 as usize).expect("already validated by validate_block");
+            let receiver_path = self.account_merkle_tree.generate_proof(tx.recipient.0 as usize).expect("already validated by validate_block");
+            // The slot this transaction's nullifier will occupy, captured
+            // *before* `apply_transaction` inserts it — its path is the
+            // non-membership witness a verifier needs to check the slot was
+            // still blank.
+            let nullifier_path = self.nullifier_merkle_tree.generate_proof(self.next_nullifier_index).expect("already validated by validate_block");
+
+            self.apply_transaction(parameters, tx, external_nullifier)
+                .expect("already validated by validate_block");
+
+            transactions.push(TransactionWitness {
+                sender_leaf,
+                sender_path,
+                receiver_leaf,
+                receiver_path,
+                nullifier_path,
+            });
+        }
+
+        let post_state_root = self.account_merkle_tree.root();
+        Some(BlockWitness { pre_state_root, post_state_root, transactions })
+    }
+
+    /// Dry-run an entire block against a scratch copy of the balances and
+    /// spent-nullifier set it would touch, without mutating `self`. Returns
+    /// `None` if any transaction in `txs` would be rejected by
+    /// [`Self::apply_transaction`] — a bad signature, a nullifier already
+    /// spent (by `self` or earlier in this same block), insufficient
+    /// balance, or exhausting the nullifier tree's remaining capacity — so
+    /// [`Self::apply_block`] can reject the whole batch before applying any
+    /// of it.
+    fn validate_block(
+        &self,
+        parameters: &Parameters,
+        txs: &[Transaction],
+        external_nullifier: ExternalNullifier,
+    ) -> Option<()> {
+        let mut balances: HashMap<AccountId, u64> = HashMap::new();
+        let mut nullifiers_in_block = HashSet::new();
+        let mut spent_in_block = 0usize;
+
+        for tx in txs {
+            let sender_public_key = self.id_to_account_info.get(&tx.sender)?.public_key;
+            let signature_valid = schnorr::Schnorr::<EdwardsProjective, Blake2s>::verify(
+                &parameters.sig_params,
+                &sender_public_key,
+                &tx.message(),
+                &tx.signature,
+            ).ok()?;
+            if !signature_valid {
+                return None;
+            }
+
+            let nullifier = tx.nullifier(parameters, external_nullifier);
+            if self.seen_nullifiers.contains(&nullifier) || !nullifiers_in_block.insert(nullifier) {
+                return None;
+            }
+            if self.next_nullifier_index + spent_in_block >= (1usize << self.height) {
+                return None;
+            }
+            spent_in_block += 1;
+
+            let sender_balance = match balances.get(&tx.sender) {
+                Some(&balance) => balance,
+                None => self.id_to_account_info.get(&tx.sender)?.balance.0,
+            };
+            let recipient_balance = match balances.get(&tx.recipient) {
+                Some(&balance) => balance,
+                None => self.id_to_account_info.get(&tx.recipient)?.balance.0,
+            };
+            let new_sender_balance = sender_balance.checked_sub(tx.amount.0)?;
+            let new_recipient_balance = recipient_balance.checked_add(tx.amount.0)?;
+            balances.insert(tx.sender, new_sender_balance);
+            balances.insert(tx.recipient, new_recipient_balance);
+        }
+
+        Some(())
     }
-}
\ No newline at end of file
+
+    /// Produce a single compact proof that the accounts named by `ids` are
+    /// present in `account_merkle_tree`, covering all of them at once rather
+    /// than handing out one full `Path` per account — the construction light
+    /// clients use for SPV partial Merkle trees. Panics if an id in `ids`
+    /// has no registered account; absence is not provable this way.
+    pub fn partial_proof(&self, parameters: &Parameters, ids: &[AccountId]) -> PartialMerkleProof {
+        let matched_leaves: HashSet<usize> = ids.iter().map(|id| id.0 as usize).collect();
+
+        // Every hash a pruned (unmatched) node needs is exactly the sibling
+        // hash recorded in some matched leaf's authentication path, so we
+        // never have to reach into the tree's internal node array.
+        let mut sibling_hash_at: HashMap<(usize, usize), <MerkleTreeCRH as CRH>::Output> = HashMap::new();
+        let mut leaf_hash_at: HashMap<usize, <MerkleTreeCRH as CRH>::Output> = HashMap::new();
+        for &leaf_index in &matched_leaves {
+            let path = self.account_merkle_tree.generate_proof(leaf_index).expect("leaf index in range");
+            let account_info = &self.id_to_account_info[&AccountId(leaf_index as u8)];
+            leaf_hash_at.insert(
+                leaf_index,
+                MerkleTreeCRH::evaluate(&parameters.leaf_crh_params, &account_info.to_bytes()).unwrap(),
+            );
+
+            let mut pos = leaf_index;
+            sibling_hash_at.insert((self.height, pos ^ 1), path.leaf_sibling_hash);
+            for (i, sibling) in path.auth_path.iter().enumerate() {
+                pos >>= 1;
+                sibling_hash_at.insert((self.height - 1 - i, pos ^ 1), *sibling);
+            }
+        }
+
+        let mut flags = Vec::new();
+        let mut hashes = Vec::new();
+        self.visit_partial_proof_node(0, 0, &matched_leaves, &sibling_hash_at, &leaf_hash_at, &mut flags, &mut hashes);
+
+        PartialMerkleProof { height: self.height, flags, hashes }
+    }
+
+    /// Depth-first visit of node `(depth, pos)` (`depth` counted from the
+    /// root), recording a "matched" bit and, for pruned subtrees and matched
+    /// leaves, the one hash a verifier needs to recompute this node without
+    /// descending further. Returns whether this node's subtree is matched.
+    fn visit_partial_proof_node(
+        &self,
+        depth: usize,
+        pos: usize,
+        matched_leaves: &HashSet<usize>,
+        sibling_hash_at: &HashMap<(usize, usize), <MerkleTreeCRH as CRH>::Output>,
+        leaf_hash_at: &HashMap<usize, <MerkleTreeCRH as CRH>::Output>,
+        flags: &mut Vec<bool>,
+        hashes: &mut Vec<<MerkleTreeCRH as CRH>::Output>,
+    ) -> bool {
+        if depth == self.height {
+            let matched = matched_leaves.contains(&pos);
+            flags.push(matched);
+            hashes.push(if matched {
+                leaf_hash_at[&pos]
+            } else {
+                sibling_hash_at[&(depth, pos)]
+            });
+            return matched;
+        }
+
+        let leaves_per_node = 1usize << (self.height - depth);
+        let (lo, hi) = (pos * leaves_per_node, (pos + 1) * leaves_per_node);
+        let matched = matched_leaves.iter().any(|&leaf| leaf >= lo && leaf < hi);
+        flags.push(matched);
+        if !matched {
+            hashes.push(if depth == 0 {
+                self.account_merkle_tree.root()
+            } else {
+                sibling_hash_at[&(depth, pos)]
+            });
+        } else {
+            self.visit_partial_proof_node(depth + 1, pos * 2, matched_leaves, sibling_hash_at, leaf_hash_at, flags, hashes);
+            self.visit_partial_proof_node(depth + 1, pos * 2 + 1, matched_leaves, sibling_hash_at, leaf_hash_at, flags, hashes);
+        }
+        matched
+    }
+}
+
+/// A compact proof of membership for an arbitrary subset of leaves in an
+/// [`State::account_merkle_tree`], built the way SPV clients build partial
+/// Merkle trees: a depth-first traversal annotated with one "matched" bit
+/// per node (does this subtree contain a requested leaf?), alongside the
+/// hashes of the pruned (unmatched) subtrees and of the matched leaves
+/// themselves — everything needed to recompute the root without the rest of
+/// the tree.
+pub struct PartialMerkleProof {
+    height: usize,
+    /// One bit per visited node, in depth-first (root-to-leaves,
+    /// left-to-right) traversal order.
+    flags: Vec<bool>,
+    /// One hash per visited node that did not recurse further: a pruned
+    /// subtree's root, or a matched leaf's hash. Consumed in the same
+    /// traversal order as `flags`.
+    hashes: Vec<<MerkleTreeCRH as CRH>::Output>,
+}
+
+impl PartialMerkleProof {
+    /// Verify this proof against `root`, given the claimed `(id, info)` pair
+    /// for every account the proof marks as matched. Returns the recomputed
+    /// root and the set of matched `AccountId`s on success.
+    ///
+    /// Rejects malformed proofs: a flag/hash stream that runs out mid-walk
+    /// or has leftover entries, duplicate `id`s in `leaves`, a matched leaf
+    /// with no entry in `leaves`, a matched leaf whose recorded hash is not
+    /// the hash of its entry in `leaves`, or a recomputed root that
+    /// disagrees with `root`.
+    pub fn verify(
+        &self,
+        parameters: &Parameters,
+        root: <MerkleTreeCRH as CRH>::Output,
+        leaves: &[(AccountId, AccountInformation)],
+    ) -> Option<(<MerkleTreeCRH as CRH>::Output, HashSet<AccountId>)> {
+        let mut leaf_info = HashMap::with_capacity(leaves.len());
+        for &(id, info) in leaves {
+            if leaf_info.insert(id, info).is_some() {
+                return None;
+            }
+        }
+
+        let mut flags = self.flags.iter().copied();
+        let mut hashes = self.hashes.iter().copied();
+        let mut matched_ids = HashSet::new();
+        let recomputed_root = self.verify_node(parameters, 0, 0, &mut flags, &mut hashes, &leaf_info, &mut matched_ids)?;
+
+        if flags.next().is_some() || hashes.next().is_some() {
+            return None;
+        }
+        if recomputed_root != root {
+            return None;
+        }
+        Some((recomputed_root, matched_ids))
+    }
+
+    fn verify_node(
+        &self,
+        parameters: &Parameters,
+        depth: usize,
+        pos: usize,
+        flags: &mut impl Iterator<Item = bool>,
+        hashes: &mut impl Iterator<Item = <MerkleTreeCRH as CRH>::Output>,
+        leaf_info: &HashMap<AccountId, AccountInformation>,
+        matched_ids: &mut HashSet<AccountId>,
+    ) -> Option<<MerkleTreeCRH as CRH>::Output> {
+        let matched = flags.next()?;
+
+        if depth == self.height {
+            if !matched {
+                return hashes.next();
+            }
+            let id = AccountId(pos as u8);
+            let info = leaf_info.get(&id)?;
+            if !matched_ids.insert(id) {
+                return None;
+            }
+            let leaf_hash = MerkleTreeCRH::evaluate(&parameters.leaf_crh_params, &info.to_bytes()).ok()?;
+            // A matched leaf's recorded hash must be the hash of the account
+            // information supplied for it.
+            if hashes.next()? != leaf_hash {
+                return None;
+            }
+            return Some(leaf_hash);
+        }
+
+        if !matched {
+            return hashes.next();
+        }
+
+        let left = self.verify_node(parameters, depth + 1, pos * 2, flags, hashes, leaf_info, matched_ids)?;
+        let right = self.verify_node(parameters, depth + 1, pos * 2 + 1, flags, hashes, leaf_info, matched_ids)?;
+        let children_bytes = ark_ff::to_bytes![left, right].ok()?;
+        MerkleTreeCRH::evaluate(&parameters.two_to_one_crh_params, &children_bytes).ok()
+    }
+}
+
+/// Everything one step of the block circuit needs to verify a single
+/// transaction within a block: the sender and receiver leaves as they stood
+/// immediately before this transaction, their authentication paths against
+/// the root at that same point (i.e. after every earlier transaction in the
+/// block has already been spliced in), and the authentication path to the
+/// nullifier-tree slot this transaction's nullifier will occupy, proving
+/// that slot was still blank beforehand.
+pub struct TransactionWitness {
+    pub sender_leaf: AccountInformation,
+    pub sender_path: merkle_tree::Path<MerkleConfig>,
+    pub receiver_leaf: AccountInformation,
+    pub receiver_path: merkle_tree::Path<MerkleConfig>,
+    pub nullifier_path: merkle_tree::Path<MerkleConfig>,
+}
+
+/// Witness for an entire rollup block: the pre- and post-state account tree
+/// roots, plus one [`TransactionWitness`] per transaction in order. A block
+/// circuit chains these, verifying each transaction's signature and balance
+/// arithmetic and splicing its updated leaves into the running root, so the
+/// whole batch is proved by a single proof instead of one proof per transfer.
+pub struct BlockWitness {
+    pub pre_state_root: <MerkleTreeCRH as CRH>::Output,
+    pub post_state_root: <MerkleTreeCRH as CRH>::Output,
+    pub transactions: Vec<TransactionWitness>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ark_std::test_rng;
+
+    /// Two funded accounts and the secret keys needed to sign transfers out
+    /// of them.
+    struct TestLedger {
+        parameters: Parameters,
+        state: State,
+        alice: AccountId,
+        bob: AccountId,
+        alice_sk: schnorr::SecretKey<EdwardsProjective>,
+    }
+
+    fn setup() -> TestLedger {
+        let rng = &mut test_rng();
+        let parameters = Parameters::setup(rng);
+        let mut state = State::new(8, &parameters);
+
+        let (alice_pk, alice_sk) =
+            schnorr::Schnorr::<EdwardsProjective, Blake2s>::keygen(&parameters.sig_params, rng).unwrap();
+        let (bob_pk, _bob_sk) =
+            schnorr::Schnorr::<EdwardsProjective, Blake2s>::keygen(&parameters.sig_params, rng).unwrap();
+
+        let alice = AccountId(0);
+        let bob = AccountId(1);
+        state.new_account(alice, alice_pk);
+        state.new_account(bob, bob_pk);
+        state.update_balance(alice, Amount(100)).unwrap();
+
+        TestLedger { parameters, state, alice, bob, alice_sk }
+    }
+
+    fn signed_transfer(
+        parameters: &Parameters,
+        sender: AccountId,
+        recipient: AccountId,
+        amount: u64,
+        nonce: u64,
+        sender_sk: &schnorr::SecretKey<EdwardsProjective>,
+    ) -> Transaction {
+        let rng = &mut test_rng();
+        let message = ark_ff::to_bytes![sender.0, recipient.0, amount, nonce].unwrap();
+        let signature = schnorr::Schnorr::<EdwardsProjective, Blake2s>::sign(
+            &parameters.sig_params,
+            sender_sk,
+            &message,
+            rng,
+        ).unwrap();
+        Transaction { sender, recipient, amount: Amount(amount), nonce, signature }
+    }
+
+    #[test]
+    fn rejects_forged_signature() {
+        let mut ledger = setup();
+        // Signed by nobody: a freshly generated key, not Alice's.
+        let rng = &mut test_rng();
+        let (_, forged_sk) =
+            schnorr::Schnorr::<EdwardsProjective, Blake2s>::keygen(&ledger.parameters.sig_params, rng).unwrap();
+        let tx = signed_transfer(&ledger.parameters, ledger.alice, ledger.bob, 10, 0, &forged_sk);
+
+        assert!(ledger.state.apply_transaction(&ledger.parameters, &tx, ExternalNullifier(0)).is_none());
+    }
+
+    #[test]
+    fn rejects_replayed_nullifier() {
+        let mut ledger = setup();
+        let tx = signed_transfer(&ledger.parameters, ledger.alice, ledger.bob, 10, 0, &ledger.alice_sk);
+
+        assert!(ledger.state.apply_transaction(&ledger.parameters, &tx, ExternalNullifier(0)).is_some());
+        // Re-submitting the exact same transfer (same nullifier) must fail,
+        // even though the signature itself is perfectly valid.
+        assert!(ledger.state.apply_transaction(&ledger.parameters, &tx, ExternalNullifier(0)).is_none());
+    }
+
+    #[test]
+    fn rejects_transaction_once_nullifier_tree_is_full() {
+        let rng = &mut test_rng();
+        let parameters = Parameters::setup(rng);
+        // Height 1, so the nullifier tree's capacity is exactly 2 leaves.
+        let mut state = State::new(2, &parameters);
+
+        let (alice_pk, alice_sk) =
+            schnorr::Schnorr::<EdwardsProjective, Blake2s>::keygen(&parameters.sig_params, rng).unwrap();
+        let (bob_pk, _) =
+            schnorr::Schnorr::<EdwardsProjective, Blake2s>::keygen(&parameters.sig_params, rng).unwrap();
+        let alice = AccountId(0);
+        let bob = AccountId(1);
+        state.new_account(alice, alice_pk);
+        state.new_account(bob, bob_pk);
+        state.update_balance(alice, Amount(100)).unwrap();
+
+        let tx0 = signed_transfer(&parameters, alice, bob, 1, 0, &alice_sk);
+        let tx1 = signed_transfer(&parameters, alice, bob, 1, 1, &alice_sk);
+        let tx2 = signed_transfer(&parameters, alice, bob, 1, 2, &alice_sk);
+
+        assert!(state.apply_transaction(&parameters, &tx0, ExternalNullifier(0)).is_some());
+        assert!(state.apply_transaction(&parameters, &tx1, ExternalNullifier(0)).is_some());
+        // The tree's two slots are both spent now; a perfectly valid,
+        // well-funded transfer must be rejected rather than panicking on
+        // the nullifier-tree `update` call.
+        assert!(state.apply_transaction(&parameters, &tx2, ExternalNullifier(0)).is_none());
+    }
+
+    #[test]
+    fn rejects_insufficient_balance() {
+        let mut ledger = setup();
+        let tx = signed_transfer(&ledger.parameters, ledger.alice, ledger.bob, 1000, 0, &ledger.alice_sk);
+
+        assert!(ledger.state.apply_transaction(&ledger.parameters, &tx, ExternalNullifier(0)).is_none());
+    }
+
+    #[test]
+    fn apply_block_is_atomic_on_failure() {
+        let mut ledger = setup();
+        let pre_state_hash = ledger.state.state_hash();
+        let pre_root = ledger.state.account_tree_root();
+
+        // First transfer is well-formed; the second would overdraw Alice.
+        // The whole block must be rejected, leaving *both* unapplied.
+        let txs = vec![
+            signed_transfer(&ledger.parameters, ledger.alice, ledger.bob, 10, 0, &ledger.alice_sk),
+            signed_transfer(&ledger.parameters, ledger.alice, ledger.bob, 1000, 1, &ledger.alice_sk),
+        ];
+
+        assert!(ledger.state.apply_block(&ledger.parameters, &txs, ExternalNullifier(0)).is_none());
+        assert_eq!(ledger.state.state_hash(), pre_state_hash);
+        assert_eq!(ledger.state.account_tree_root(), pre_root);
+    }
+
+    #[test]
+    fn state_hash_tracks_every_mutation() {
+        let mut ledger = setup();
+        let after_setup = ledger.state.state_hash();
+
+        let tx = signed_transfer(&ledger.parameters, ledger.alice, ledger.bob, 10, 0, &ledger.alice_sk);
+        assert!(ledger.state.apply_transaction(&ledger.parameters, &tx, ExternalNullifier(0)).is_some());
+
+        // Only `State` methods can touch `account_merkle_tree`/
+        // `id_to_account_info` now, so `state_hash()` must have moved.
+        assert_ne!(ledger.state.state_hash(), after_setup);
+    }
+
+    #[test]
+    fn partial_proof_round_trips() {
+        let ledger = setup();
+        let alice_info = *ledger.state.account_info(ledger.alice).unwrap();
+        let bob_info = *ledger.state.account_info(ledger.bob).unwrap();
+        let leaves = [(ledger.alice, alice_info), (ledger.bob, bob_info)];
+
+        let proof = ledger.state.partial_proof(&ledger.parameters, &[ledger.alice, ledger.bob]);
+        let result = proof.verify(&ledger.parameters, ledger.state.account_tree_root(), &leaves);
+
+        let (_, matched) = result.expect("honestly produced proof must verify");
+        assert!(matched.contains(&ledger.alice));
+        assert!(matched.contains(&ledger.bob));
+    }
+
+    #[test]
+    fn partial_proof_rejects_tampered_hash() {
+        let ledger = setup();
+        let alice_info = *ledger.state.account_info(ledger.alice).unwrap();
+        let bob_info = *ledger.state.account_info(ledger.bob).unwrap();
+        let leaves = [(ledger.alice, alice_info), (ledger.bob, bob_info)];
+
+        let mut proof = ledger.state.partial_proof(&ledger.parameters, &[ledger.alice, ledger.bob]);
+        // Corrupt one recorded hash (Alice's leaf hash); it no longer
+        // matches the account information supplied for her.
+        proof.hashes[0] = Default::default();
+
+        let result = proof.verify(&ledger.parameters, ledger.state.account_tree_root(), &leaves);
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn partial_proof_rejects_missing_leaf_info() {
+        let ledger = setup();
+        let alice_info = *ledger.state.account_info(ledger.alice).unwrap();
+
+        let proof = ledger.state.partial_proof(&ledger.parameters, &[ledger.alice, ledger.bob]);
+        // Bob is matched by the proof but his account info is withheld.
+        let leaves = [(ledger.alice, alice_info)];
+
+        let result = proof.verify(&ledger.parameters, ledger.state.account_tree_root(), &leaves);
+        assert!(result.is_none());
+    }
+}